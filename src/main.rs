@@ -3,15 +3,27 @@ extern crate wayland_client;
 extern crate tempfile;
 extern crate byteorder;
 extern crate rand;
+extern crate xkbcommon;
+extern crate memmap;
+extern crate calloop;
+extern crate wayland_protocols;
 
 use byteorder::{NativeEndian, WriteBytesExt};
+use std::cell::RefCell;
 use std::fs::File;
 use std::io::Write;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::rc::Rc;
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use calloop::EventLoop;
+use calloop::generic::{Generic, Interest, Mode};
+use calloop::timer::Timer;
 use wayland_client::EnvHandler;
-use wayland_client::protocol::{wl_compositor, wl_pointer, wl_seat, wl_shell, wl_shell_surface,
-                               wl_shm, wl_keyboard};
+use wayland_client::protocol::{wl_buffer, wl_callback, wl_compositor, wl_pointer, wl_seat, wl_shm,
+                               wl_shm_pool, wl_surface, wl_keyboard};
+use wayland_protocols::xdg_shell::client::{xdg_wm_base, xdg_surface, xdg_toplevel};
+use xkbcommon::xkb;
 
 // buffer (and window) width and height
 const BUF_X: usize = 640;
@@ -22,7 +34,7 @@ wayland_env!(
     WaylandEnvironment,
     compositor: wl_compositor::WlCompositor,
     seat: wl_seat::WlSeat,
-    shell: wl_shell::WlShell,
+    xdg_wm_base: xdg_wm_base::XdgWmBase,
     shm: wl_shm::WlShm
 );
 
@@ -34,9 +46,53 @@ struct Rect {
     h: u32,
 }
 
+// check if pixel (i, j) falls within rect; shared by the draw loop and the pointer's drag grab
+fn is_coords_in_rect(rect: &Rect, i: u32, j: u32) -> bool {
+    i > rect.x && i < rect.x + rect.w && j > rect.y && j < rect.y + rect.h
+}
+
 // object we will pass around between draw loop and user input handlers
 struct AppState {
-    rect: Rect
+    rect: Rect,
+    // current window dimensions, as last negotiated with the compositor via xdg_toplevel.configure
+    width: u32,
+    height: u32,
+    xkb_context: xkb::Context,
+    xkb_state: Option<xkb::State>,
+    // repeat rate (chars/sec) and delay (ms) last reported by the keyboard's repeat_info event
+    repeat_rate: i32,
+    repeat_delay: i32,
+    // the keysym currently being auto-repeated, if any; cleared on key release
+    active_repeat_key: Option<xkb::Keysym>,
+    // set whenever the rect (or window size) changes; cleared once a redraw has been queued
+    dirty: bool,
+    // last position reported by a pointer enter or motion event
+    pointer_x: u32,
+    pointer_y: u32,
+    // true while BTN_LEFT is held down after being pressed inside the rect
+    dragging: bool,
+    // offset between the pointer and the rect's origin, captured when the drag started
+    drag_offset: (u32, u32),
+}
+
+// one of the two SHM-backed buffers the compositor can be reading from while we fill the other
+struct BufferSlot {
+    buffer: wl_buffer::WlBuffer,
+    released: Arc<RwLock<bool>>,
+}
+
+// everything a redraw needs to touch; shared (via Rc<RefCell<_>>) between the wayland fd source,
+// the ping source the repeat timer wakes, and the initial draw before the loop starts
+struct RenderState {
+    event_queue: wayland_client::EventQueue,
+    display: wayland_client::Display,
+    surface: wl_surface::WlSurface,
+    tmp: File,
+    pool: wl_shm_pool::WlShmPool,
+    buffers: Vec<BufferSlot>,
+    // dimensions the SHM pool and buffers are currently allocated for
+    buf_width: i32,
+    buf_height: i32,
 }
 
 // Atomic reference cell and reader-writer lock to safely share AppState across threads
@@ -50,11 +106,137 @@ impl AppState {
                 y: 0,
                 w: 50,
                 h: 50,
-            }
+            },
+            width: BUF_X as u32,
+            height: BUF_Y as u32,
+            xkb_context: xkb::Context::new(xkb::CONTEXT_NO_FLAGS),
+            xkb_state: None,
+            repeat_rate: 25,
+            repeat_delay: 600,
+            active_repeat_key: None,
+            dirty: true,
+            pointer_x: 0,
+            pointer_y: 0,
+            dragging: false,
+            drag_offset: (0, 0),
         }))
     }
 }
 
+// applies the movement for a single arrow-key tick; shared between the key handler and the
+// repeat timer so holding a key behaves identically to tapping it repeatedly
+fn apply_key_movement(rect: &mut Rect, keysym: xkb::Keysym, width: u32, height: u32) {
+    match keysym {
+        xkb::KEY_Up => rect.y = rect.y.saturating_sub(5),
+        xkb::KEY_Down => rect.y = (rect.y + 5).min(height - rect.h),
+        xkb::KEY_Left => rect.x = rect.x.saturating_sub(5),
+        xkb::KEY_Right => rect.x = (rect.x + 5).min(width - rect.w),
+        _ => (),
+    }
+}
+
+// keeps the rect within a window that just shrank, so a resize never leaves it off-screen
+fn clamp_rect_to_window(rect: &mut Rect, width: u32, height: u32) {
+    rect.w = rect.w.min(width);
+    rect.h = rect.h.min(height);
+    rect.x = rect.x.min(width - rect.w);
+    rect.y = rect.y.min(height - rect.h);
+}
+
+// resizes the SHM buffers to match AppState if needed, then redraws and commits a fresh frame
+// if something is dirty and the compositor is ready for one; called from the wayland fd source
+// (after a dispatch), from the repeat timer's ping, and once up front to draw the first frame
+fn maybe_render(
+    render: &mut RenderState,
+    app_state: &ArcRwlAppState,
+    frame_ready: &Arc<RwLock<bool>>,
+) {
+    let (want_width, want_height) = {
+        let readable_app_state = app_state.read().unwrap();
+        (readable_app_state.width as i32, readable_app_state.height as i32)
+    };
+
+    // recreate the shared buffers at the compositor's latest suggested size, growing the pool
+    // when they no longer fit in it
+    if want_width != render.buf_width || want_height != render.buf_height {
+        let slot_size = want_width * want_height * 4;
+        // wl_shm_pool.resize can only make a pool bigger; the tempfile's length tracks the
+        // pool's size, so a smaller window keeps using the existing pool
+        let pool_size = render.tmp.metadata().expect("Failed to stat shm tempfile").len();
+        if (slot_size * 2) as u64 > pool_size {
+            render
+                .tmp
+                .set_len((slot_size * 2) as u64)
+                .expect("Failed to resize shm tempfile");
+            render.pool.resize(slot_size * 2);
+        }
+
+        render.buffers = (0..2)
+            .map(|i| {
+                let buffer = render.pool.create_buffer(
+                    i * slot_size,
+                    want_width,
+                    want_height,
+                    want_width * 4,
+                    wl_shm::Format::Argb8888,
+                ).expect("The pool cannot be already dead");
+
+                let released = Arc::new(RwLock::new(true));
+                render.event_queue.register(
+                    &buffer,
+                    create_buffer_event_hander(),
+                    released.clone(),
+                );
+
+                BufferSlot { buffer, released }
+            })
+            .collect();
+
+        render.buf_width = want_width;
+        render.buf_height = want_height;
+    }
+
+    // only redraw once something actually changed, and only submit a new frame once the
+    // compositor is done with the last one and a buffer is free
+    let is_dirty = app_state.read().unwrap().dirty;
+    let is_frame_ready = *frame_ready.read().unwrap();
+
+    if !(is_dirty && is_frame_ready) {
+        return;
+    }
+
+    let slot_size = (render.buf_width * render.buf_height * 4) as u64;
+    let free_slot_index = render
+        .buffers
+        .iter()
+        .position(|slot| *slot.released.read().unwrap());
+
+    let index = match free_slot_index {
+        Some(index) => index,
+        None => return,
+    };
+
+    draw(app_state, &mut render.tmp, index as u64 * slot_size);
+    *render.buffers[index].released.write().unwrap() = false;
+
+    render.surface.attach(Some(&render.buffers[index].buffer), 0, 0);
+    render
+        .surface
+        .damage_buffer(0, 0, render.buf_width, render.buf_height)
+        .expect("Failed to damage buffer");
+
+    let frame_cb = render.surface.frame();
+    render
+        .event_queue
+        .register(&frame_cb, create_frame_event_hander(), frame_ready.clone());
+    *frame_ready.write().unwrap() = false;
+
+    render.surface.commit();
+    render.display.flush().expect("Error flushing display");
+
+    app_state.write().unwrap().dirty = false;
+}
+
 fn main() {
 
     // Connect to wayland server
@@ -80,22 +262,52 @@ fn main() {
         .unwrap();
 
     let surface = env.compositor.create_surface();
-    let shell_surface = env.shell.get_shell_surface(&surface);
-
-    let pool = env.shm
-        .create_pool(tmp.as_raw_fd(), (BUF_X * BUF_Y * 4) as i32);
-
-    let buffer = pool.create_buffer(
-        0,
-        BUF_X as i32,
-        BUF_Y as i32,
-        (BUF_X * 4) as i32,
-        wl_shm::Format::Argb8888,
-    ).expect("The pool cannot be already dead");
-
-    // make our surface as a toplevel one
-    shell_surface.set_toplevel();
-    
+    let xdg_surface = env.xdg_wm_base.get_xdg_surface(&surface);
+    let xdg_toplevel = xdg_surface.get_toplevel();
+
+    // two buffers of one frame's worth of pixels each, so the compositor can read one while we
+    // draw into the other
+    let buffer_size = (BUF_X * BUF_Y * 4) as i32;
+    tmp.set_len((buffer_size * 2) as u64)
+        .expect("Failed to size shm tempfile");
+
+    let pool = env.shm.create_pool(tmp.as_raw_fd(), buffer_size * 2);
+
+    let mut buffers: Vec<BufferSlot> = (0..2)
+        .map(|i| {
+            let buffer = pool.create_buffer(
+                i * buffer_size,
+                BUF_X as i32,
+                BUF_Y as i32,
+                (BUF_X * 4) as i32,
+                wl_shm::Format::Argb8888,
+            ).expect("The pool cannot be already dead");
+
+            let released = Arc::new(RwLock::new(true));
+            event_queue.register(&buffer, create_buffer_event_hander(), released.clone());
+
+            BufferSlot { buffer, released }
+        })
+        .collect();
+
+    let app_state = AppState::new();
+
+    // answer pings and acknowledge configures so the compositor keeps this surface mapped
+    event_queue.register(&env.xdg_wm_base, create_xdg_wm_base_event_hander(), ());
+    event_queue.register(&xdg_surface, create_xdg_surface_event_hander(), ());
+    event_queue.register(
+        &xdg_toplevel,
+        create_xdg_toplevel_event_hander(),
+        app_state.clone(),
+    );
+
+    // commit the surface with no buffer attached to trigger the initial configure, then ack it; the
+    // compositor will not let us attach a buffer before this handshake completes
+    surface.commit();
+    event_queue
+        .sync_roundtrip()
+        .expect("Failed to sync with wayland server");
+
     let pointer = env.seat
         .get_pointer()
         .expect("Seat cannot be already destroyed.");
@@ -104,29 +316,111 @@ fn main() {
         .get_keyboard()
         .expect("Seat cannot be already destroyed.");
 
-    let app_state = AppState::new();
+    // event loop multiplexing the wayland connection with the key-repeat timer
+    let mut event_loop: EventLoop<()> = EventLoop::new().expect("Failed to create event loop");
+
+    // wakes the render pass from sources, like the repeat timer, that have no direct access to
+    // the RenderState resources (those are owned by the wayland fd and ping sources below)
+    let (ping, ping_source) = calloop::ping::make_ping().expect("Failed to create ping");
+
+    let (repeat_timer, repeat_timer_handle) = Timer::new().expect("Failed to create repeat timer");
+    event_loop
+        .handle()
+        .insert_source(repeat_timer, {
+            let app_state = app_state.clone();
+            let repeat_timer_handle = repeat_timer_handle.clone();
+            let ping = ping.clone();
+            move |keysym, _metadata, _shared| {
+                let mut writable_app_state = app_state.write().unwrap();
+
+                // the key may have been released (or a different key pressed) since this tick
+                // was scheduled; only keep repeating while it is still the active key
+                if writable_app_state.active_repeat_key != Some(keysym) {
+                    return;
+                }
 
-    event_queue.register(&shell_surface, create_shell_surface_event_hander(), ());
-    event_queue.register(&pointer, create_pointer_event_hander(), app_state.clone());
-    event_queue.register(&keyboard, create_keyboard_event_hander(), app_state.clone());
+                let (width, height) = (writable_app_state.width, writable_app_state.height);
+                apply_key_movement(&mut writable_app_state.rect, keysym, width, height);
+                writable_app_state.dirty = true;
 
-    // infinite loop to draw and receive user input
-    loop {
+                let period_ms = 1000 / writable_app_state.repeat_rate.max(1) as u64;
+                repeat_timer_handle.add_timeout(Duration::from_millis(period_ms), keysym);
 
-        draw(&app_state, &mut tmp);
+                // the wayland fd may stay silent while a key is held; ping so the held-down
+                // movement above actually gets drawn and committed
+                ping.ping();
+            }
+        })
+        .expect("Failed to insert repeat timer source");
 
-        surface.attach(Some(&buffer), 0, 0);
-        surface.damage_buffer(0, 0, BUF_X as i32, BUF_Y as i32).expect("Failed to damage buffer");
-        surface.commit();
+    event_queue.register(&pointer, create_pointer_event_hander(), app_state.clone());
+    event_queue.register(
+        &keyboard,
+        create_keyboard_event_hander(repeat_timer_handle),
+        app_state.clone(),
+    );
 
-        display.flush().expect("Error flushing display");
+    // set once a frame callback fires (or at startup); cleared as soon as a frame is submitted,
+    // so we never have more than one frame in flight with the compositor
+    let frame_ready = Arc::new(RwLock::new(true));
+
+    let mut render_state = RenderState {
+        event_queue,
+        display,
+        surface,
+        tmp,
+        pool,
+        buffers,
+        buf_width: BUF_X as i32,
+        buf_height: BUF_Y as i32,
+    };
+
+    // draw the first frame immediately, rather than waiting on a frame callback or timer tick
+    maybe_render(&mut render_state, &app_state, &frame_ready);
+
+    let render_state = Rc::new(RefCell::new(render_state));
+
+    event_loop
+        .handle()
+        .insert_source(ping_source, {
+            let app_state = app_state.clone();
+            let frame_ready = frame_ready.clone();
+            let render_state = render_state.clone();
+            move |_event, _metadata, _shared| {
+                maybe_render(&mut render_state.borrow_mut(), &app_state, &frame_ready);
+            }
+        })
+        .expect("Failed to insert ping source");
+
+    let wayland_fd = render_state.borrow().display.get_connection_fd();
+    event_loop
+        .handle()
+        .insert_source(
+            Generic::from_fd(wayland_fd, Interest::Readable, Mode::Level),
+            {
+                let app_state = app_state.clone();
+                let frame_ready = frame_ready.clone();
+                let render_state = render_state.clone();
+                move |_event, _metadata, _shared| {
+                    let mut render = render_state.borrow_mut();
+                    render.event_queue.dispatch().expect("Event queue dispatch failed");
+                    maybe_render(&mut render, &app_state, &frame_ready);
+                }
+            },
+        )
+        .expect("Failed to insert wayland source");
 
-        event_queue.dispatch().expect("Event queue dispatch failed");
+    // run the event loop forever, driven by wayland activity and key-repeat ticks
+    loop {
+        event_loop
+            .dispatch(None, &mut ())
+            .expect("Event loop dispatch failed");
     }
 }
 
-// application draw logic to run on each frame
-fn draw(app_state: &ArcRwlAppState, tmp_file: &mut File) {
+// application draw logic to run on each frame; `offset` selects which of the two buffers'
+// regions of `tmp_file` to write into
+fn draw(app_state: &ArcRwlAppState, tmp_file: &mut File, offset: u64) {
     use std::io::{Seek, SeekFrom};
 
     // get AppState from lock, using read() as to not block other readers
@@ -138,21 +432,19 @@ fn draw(app_state: &ArcRwlAppState, tmp_file: &mut File) {
         readable_app_state.rect.y
     );
 
-    // check if pixel in within rectangle
-    fn is_coords_in_rect(rect: &Rect, i: u32, j: u32) -> bool {
-        i > rect.x && i < rect.x + rect.w && j > rect.y && j < rect.y + rect.h
-    }
+    // go to start of this frame's buffer region
+    tmp_file.seek(SeekFrom::Start(offset)).unwrap();
 
-    // go to start of buffer
-    tmp_file.seek(SeekFrom::Start(0)).unwrap();
+    let width = readable_app_state.width as usize;
+    let height = readable_app_state.height as usize;
 
     let mut pixels: Vec<u32> = Vec::new();
-    pixels.reserve_exact(BUF_X * BUF_Y);
+    pixels.reserve_exact(width * height);
 
     // draw random pixels into buffer, white pixel inside Rect based on current app state
-    for i in 0..(BUF_X * BUF_Y) {
-        let x = (i % BUF_X) as u32;
-        let y = (i / BUF_Y) as u32;
+    for i in 0..(width * height) {
+        let x = (i % width) as u32;
+        let y = (i / width) as u32;
 
         let pixel_value = match is_coords_in_rect(&readable_app_state.rect, x, y) {
             true => 0xFFFFFFFF,
@@ -165,28 +457,121 @@ fn draw(app_state: &ArcRwlAppState, tmp_file: &mut File) {
     tmp_file.flush().unwrap();
 }
 
-fn create_shell_surface_event_hander() -> wl_shell_surface::Implementation<()> {
-    wl_shell_surface::Implementation {
-        ping: |_, _, shell_surface, serial| {
-            shell_surface.pong(serial);
+fn create_xdg_wm_base_event_hander() -> xdg_wm_base::Implementation<()> {
+    xdg_wm_base::Implementation {
+        ping: |_, _, xdg_wm_base, serial| {
+            xdg_wm_base.pong(serial);
+        },
+    }
+}
+
+fn create_buffer_event_hander() -> wl_buffer::Implementation<Arc<RwLock<bool>>> {
+    wl_buffer::Implementation {
+        release: |_, released, _buffer| {
+            *released.write().unwrap() = true;
         },
-        configure: |_, _, _, _, _, _| {},
-        popup_done: |_, _, _| {},
     }
 }
 
+fn create_frame_event_hander() -> wl_callback::Implementation<Arc<RwLock<bool>>> {
+    wl_callback::Implementation {
+        done: |_, frame_ready, _callback, _time| {
+            *frame_ready.write().unwrap() = true;
+        },
+    }
+}
+
+fn create_xdg_surface_event_hander() -> xdg_surface::Implementation<()> {
+    xdg_surface::Implementation {
+        configure: |_, _, xdg_surface, serial| {
+            // must be acked before the next buffer attached to this surface is committed
+            xdg_surface.ack_configure(serial);
+        },
+    }
+}
+
+fn create_xdg_toplevel_event_hander() -> xdg_toplevel::Implementation<ArcRwlAppState> {
+    xdg_toplevel::Implementation::<ArcRwlAppState> {
+        configure: |_, app_state, _xdg_toplevel, width, height, _states| {
+            // a zero dimension means the compositor has no preference; keep our current size
+            if width == 0 || height == 0 {
+                return;
+            }
+
+            let mut writable_app_state = app_state.write().unwrap();
+            writable_app_state.width = width as u32;
+            writable_app_state.height = height as u32;
+            clamp_rect_to_window(
+                &mut writable_app_state.rect,
+                writable_app_state.width,
+                writable_app_state.height,
+            );
+            writable_app_state.dirty = true;
+        },
+        close: |_, _, _xdg_toplevel| {},
+    }
+}
+
+// evdev code for the left mouse button, as carried by wl_pointer's button event
+const BTN_LEFT: u32 = 0x110;
+
 fn create_pointer_event_hander() -> wl_pointer::Implementation<ArcRwlAppState> {
     wl_pointer::Implementation::<ArcRwlAppState> {
-        enter: |_, _, _pointer, _serial, _surface, x, y| {},
+        enter: |_, app_state, _pointer, _serial, _surface, x, y| {
+            let mut writable_app_state = app_state.write().unwrap();
+            writable_app_state.pointer_x = x as u32;
+            writable_app_state.pointer_y = y as u32;
+        },
         leave: |_, _, _pointer, _serial, _surface| {},
         motion: |_, app_state, _pointer, _time, x, y| {
+            let mut writable_app_state = app_state.write().unwrap();
+            writable_app_state.pointer_x = x as u32;
+            writable_app_state.pointer_y = y as u32;
+
+            // only follow the pointer while it is dragging the rect around
+            if writable_app_state.dragging {
+                let (offset_x, offset_y) = writable_app_state.drag_offset;
+                let (width, height) = (writable_app_state.width, writable_app_state.height);
+                let rect_w = writable_app_state.rect.w;
+                let rect_h = writable_app_state.rect.h;
+                writable_app_state.rect.x = writable_app_state
+                    .pointer_x
+                    .saturating_sub(offset_x)
+                    .min(width - rect_w);
+                writable_app_state.rect.y = writable_app_state
+                    .pointer_y
+                    .saturating_sub(offset_y)
+                    .min(height - rect_h);
+                writable_app_state.dirty = true;
+            }
+        },
+        button: |_, app_state, _pointer, _serial, _time, button, state| {
+            use wl_pointer::ButtonState;
+
+            if button != BTN_LEFT {
+                return;
+            }
 
-            // sets Rect's top-left coordinates to that of the pointer
             let mut writable_app_state = app_state.write().unwrap();
-            writable_app_state.rect.x = x as u32;
-            writable_app_state.rect.y = y as u32;
+
+            match state {
+                ButtonState::Pressed => {
+                    let (pointer_x, pointer_y) =
+                        (writable_app_state.pointer_x, writable_app_state.pointer_y);
+
+                    if is_coords_in_rect(&writable_app_state.rect, pointer_x, pointer_y) {
+                        writable_app_state.drag_offset = (
+                            pointer_x - writable_app_state.rect.x,
+                            pointer_y - writable_app_state.rect.y,
+                        );
+                        writable_app_state.dragging = true;
+                    }
+                },
+                ButtonState::Released => {
+                    writable_app_state.dragging = false;
+                },
+            }
         },
-        button: |_, _, _pointer, _serial, _time, button, state| {},
         axis: |_, _, _, _, _, _| {},
         frame: |_, _, _| {},
         axis_source: |_, _, _, _| {},
@@ -195,36 +580,96 @@ fn create_pointer_event_hander() -> wl_pointer::Implementation<ArcRwlAppState> {
     }
 }
 
-fn create_keyboard_event_hander() -> wl_keyboard::Implementation<ArcRwlAppState> {
+fn create_keyboard_event_hander(
+    repeat_timer_handle: calloop::timer::TimerHandle<xkb::Keysym>,
+) -> wl_keyboard::Implementation<ArcRwlAppState> {
     wl_keyboard::Implementation::<ArcRwlAppState> {
-        keymap: |_, _, _keyboard, _serial, _surface, keys| {},
+        keymap: |_, app_state, _keyboard, format, fd, size| {
+            use wl_keyboard::KeymapFormat;
+
+            // only the text xkb v1 format is understood here
+            if format != KeymapFormat::XkbV1 {
+                return;
+            }
+
+            // per the wl_keyboard protocol, this fd is ours to map and then close
+            let map = unsafe {
+                let file = File::from_raw_fd(fd);
+                memmap::Mmap::open(&file, memmap::Protection::Read)
+                    .expect("Failed to mmap keymap fd")
+                // `file` drops here, closing the fd now that it's mapped
+            };
+
+            // `size` includes the keymap string's trailing NUL, which CString::new (used
+            // internally when compiling the keymap) rejects as an interior NUL
+            let map_bytes = &unsafe { map.as_slice() }[..size - 1];
+
+            let mut writable_app_state = app_state.write().unwrap();
+
+            let keymap = xkb::Keymap::new_from_string(
+                &writable_app_state.xkb_context,
+                unsafe { ::std::str::from_utf8_unchecked(map_bytes) }.to_owned(),
+                xkb::KEYMAP_FORMAT_TEXT_V1,
+                xkb::KEYMAP_COMPILE_NO_FLAGS,
+            ).expect("Failed to compile xkb keymap");
+
+            // State::new takes its own reference on the keymap, so it need not be kept around
+            writable_app_state.xkb_state = Some(xkb::State::new(&keymap));
+        },
         enter: |_, _, _keyboard, _serial, _surface, keys| {},
-        leave: |_, _, _keyboard, _serial, _surface | {}, 
+        leave: |_, app_state, _keyboard, _serial, _surface| {
+            // the Released event for whatever was held won't arrive once focus is gone
+            app_state.write().unwrap().active_repeat_key = None;
+        },
         key: |_, app_state, _keyboard, _serial, _time, key, state| {
             use wl_keyboard::KeyState;
 
             let mut writable_app_state = app_state.write().unwrap();
 
-            // update rect coordinates based on keyboard arrow keys
-            match (state, key) {
-                (KeyState::Released, 103) => {
-                    writable_app_state.rect.y = writable_app_state.rect.y - 5;
-                },
-                (KeyState::Released, 108) => {
-                    writable_app_state.rect.y = writable_app_state.rect.y + 5;
+            // wire keycodes are evdev keycodes offset by 8 from the xkb keycode space
+            let keysym = match writable_app_state.xkb_state {
+                Some(ref xkb_state) => xkb_state.key_get_one_sym(key + 8),
+                None => return,
+            };
+
+            match state {
+                KeyState::Pressed => {
+                    let (width, height) = (writable_app_state.width, writable_app_state.height);
+                    apply_key_movement(&mut writable_app_state.rect, keysym, width, height);
+                    writable_app_state.dirty = true;
+
+                    // arm the repeat timer to fire again after the initial delay; keys that do
+                    // not move the rect are never repeated
+                    let is_movement_key = match keysym {
+                        xkb::KEY_Up | xkb::KEY_Down | xkb::KEY_Left | xkb::KEY_Right => true,
+                        _ => false,
+                    };
+                    if is_movement_key {
+                        writable_app_state.active_repeat_key = Some(keysym);
+                        let delay = writable_app_state.repeat_delay.max(0) as u64;
+                        repeat_timer_handle.add_timeout(Duration::from_millis(delay), keysym);
+                    }
                 },
-                (KeyState::Released, 105) => {
-                    writable_app_state.rect.x = writable_app_state.rect.x - 5;
+                KeyState::Released => {
+                    if writable_app_state.active_repeat_key == Some(keysym) {
+                        writable_app_state.active_repeat_key = None;
+                    }
                 },
-                (KeyState::Released, 106) => {
-                    writable_app_state.rect.x = writable_app_state.rect.x + 5;
-                }
-                _ => ()
-            };
+            }
 
             println!("Key {} was {:?}.", key, state);
         },
-        modifiers: |_, _, _keyboard, _serial, mods_depressed, mods_latched, mods_locked, group| {},
-        repeat_info: |_, _, _keyboard, _serial, _surface| {}
+        modifiers: |_, app_state, _keyboard, _serial, mods_depressed, mods_latched, mods_locked, group| {
+            let mut writable_app_state = app_state.write().unwrap();
+
+            if let Some(ref mut xkb_state) = writable_app_state.xkb_state {
+                xkb_state.update_mask(mods_depressed, mods_latched, mods_locked, 0, 0, group);
+            }
+        },
+        repeat_info: |_, app_state, _keyboard, rate, delay| {
+            let mut writable_app_state = app_state.write().unwrap();
+            writable_app_state.repeat_rate = rate;
+            writable_app_state.repeat_delay = delay;
+        }
     }
 }